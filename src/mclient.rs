@@ -28,18 +28,23 @@ use matrix_sdk::{
     attachment::AttachmentConfig,
     config::{RequestConfig, StoreConfig, SyncSettings},
     instant::Duration,
-    // room,
-    // room::Room,
+    room::Room,
     ruma::{
+        api::client::{
+            account::register::v3::Request as RegistrationRequest,
+            message::get_message_events::v3::Direction,
+            room::{create_room::v3::RoomPreset, Visibility},
+            uiaa::{AuthData, Dummy, RegistrationToken},
+        },
         events::room::message::{
             EmoteMessageEventContent, MessageType, NoticeMessageEventContent,
-            RoomMessageEventContent, TextMessageEventContent,
+            RoomMessageEventContent, SyncRoomMessageEvent, TextMessageEventContent,
         },
-        RoomId,
-        // OwnedRoomId, OwnedRoomOrAliasId, OwnedServerName,
+        assign, OwnedRoomId, RoomAliasId, RoomId, RoomOrAliasId, UInt,
+        // OwnedServerName,
         // device_id, room_id, session_id, user_id, OwnedDeviceId, OwnedUserId,
     },
-    Client,
+    Client, LoopCtrl,
     // Session,
 };
 
@@ -118,6 +123,170 @@ pub(crate) async fn login<'a>(
     Ok(client)
 }
 
+/// Constructor for matrix-sdk async Client, based on the SSO login flow.
+/// Spins up a transient local redirect HTTP server (via the SDK's SSO login
+/// helper), prints the homeserver-provided SSO URL for the user to open in a
+/// browser, and waits for the `loginToken` to come back on the redirect.
+pub(crate) async fn login_sso<'a>(
+    gs: &'a mut GlobalState,
+    homeserver: &Url,
+    device: &str,
+    room_default: &str,
+    sso_server_port: Option<u16>,
+    sso_identity_provider_id: Option<&str>,
+) -> Result<Client, Error> {
+    let client = create_client(homeserver.clone(), gs).await?;
+    debug!("About to call login_sso()");
+
+    let mut login_builder = client.login_sso(|sso_url| async move {
+        info!("Open this URL in your browser to complete SSO login: {}", sso_url);
+        println!("Open this URL in your browser to complete SSO login:\n{}", sso_url);
+        Ok(())
+    });
+    login_builder = login_builder.initial_device_display_name(device);
+    if let Some(port) = sso_server_port {
+        login_builder = login_builder.server_url(&format!("http://localhost:{}", port));
+    }
+    if let Some(idp) = sso_identity_provider_id {
+        login_builder = login_builder.identity_provider_id(idp);
+    }
+    let response = login_builder.send().await;
+    debug!("Called login_sso()");
+
+    match response {
+        Ok(n) => debug!("login_sso() successful with response {:?}.", n),
+        Err(e) => {
+            error!("Error: {}", e);
+            return Err(Error::LoginFailed);
+        }
+    }
+    let session = client
+        .session()
+        .expect("error: client not logged in correctly. No session.");
+    info!("device id = {}", session.device_id);
+    info!("credentials file = {:?}", gs.credentials_file_path);
+
+    Credentials::new(
+        homeserver.clone(),
+        session.user_id.clone(),
+        session.access_token.clone(),
+        session.device_id.clone(),
+        room_default.to_string(),
+        session.refresh_token.clone(),
+    )
+    .save(&gs.credentials_file_path)?;
+    info!(
+        "new credentials file created = {:?}",
+        gs.credentials_file_path
+    );
+    sync_once(&client, get_timeout(gs), gs.ap.sync).await?;
+    Ok(client)
+}
+
+/// Constructor for matrix-sdk async Client that creates a brand-new account,
+/// driving the User-Interactive Auth (UIAA) flows the homeserver asks for.
+/// Supports the `m.login.dummy` stage and, if a `registration_token` is
+/// supplied, the `m.login.registration_token` stage. Resubmits the
+/// registration request with the server's `session` id and the
+/// stage-specific `AuthData` until a real `Session` comes back.
+pub(crate) async fn register<'a>(
+    gs: &'a mut GlobalState,
+    homeserver: &Url,
+    username: &str,
+    password: &str,
+    device: &str,
+    room_default: &str,
+    registration_token: Option<&str>,
+) -> Result<Client, Error> {
+    let client = create_client(homeserver.clone(), gs).await?;
+    debug!("About to call register()");
+
+    // Bails out rather than looping forever if the server keeps asking for
+    // the same stage again (a rejected token, or a stage we don't support).
+    const MAX_UIAA_ATTEMPTS: u32 = 10;
+    let mut uiaa_auth: Option<AuthData> = None;
+    let mut last_stage_tried: Option<&'static str> = None;
+    let mut attempts = 0u32;
+    let session = loop {
+        let request = assign!(RegistrationRequest::new(), {
+            username: Some(username),
+            password: Some(password),
+            initial_device_display_name: Some(device),
+            auth: uiaa_auth.clone(),
+        });
+
+        match client.register(request).await {
+            Ok(_) => {
+                break client
+                    .session()
+                    .expect("error: client not logged in correctly. No session.");
+            }
+            Err(e) => {
+                let Some(uiaa_info) = e.uiaa_response() else {
+                    error!("Error: {}", e);
+                    return Err(Error::LoginFailed);
+                };
+                debug!("register(): server wants UIAA stages {:?}", uiaa_info.flows);
+                attempts += 1;
+                if attempts > MAX_UIAA_ATTEMPTS {
+                    error!("Error: exceeded {} UIAA registration attempts", MAX_UIAA_ATTEMPTS);
+                    return Err(Error::LoginFailed);
+                }
+                let session_id = uiaa_info.session.clone().ok_or(Error::LoginFailed)?;
+                let completed = &uiaa_info.completed;
+                let next_stage = if !completed.iter().any(|s| s == "m.login.registration_token")
+                    && registration_token.is_some()
+                {
+                    "m.login.registration_token"
+                } else if !completed.iter().any(|s| s == "m.login.dummy") {
+                    "m.login.dummy"
+                } else {
+                    error!(
+                        "Error: homeserver requires unsupported UIAA stages {:?}",
+                        uiaa_info.flows
+                    );
+                    return Err(Error::LoginFailed);
+                };
+                if last_stage_tried == Some(next_stage) {
+                    error!(
+                        "Error: homeserver rejected our {} attempt twice, giving up",
+                        next_stage
+                    );
+                    return Err(Error::LoginFailed);
+                }
+                last_stage_tried = Some(next_stage);
+                uiaa_auth = Some(if next_stage == "m.login.registration_token" {
+                    let mut token_auth = RegistrationToken::new(registration_token.unwrap());
+                    token_auth.session = Some(session_id);
+                    AuthData::RegistrationToken(token_auth)
+                } else {
+                    let mut dummy_auth = Dummy::new();
+                    dummy_auth.session = Some(session_id);
+                    AuthData::Dummy(dummy_auth)
+                });
+            }
+        }
+    };
+    info!("device id = {}", session.device_id);
+    info!("credentials file = {:?}", gs.credentials_file_path);
+
+    Credentials::new(
+        homeserver.clone(),
+        session.user_id.clone(),
+        session.access_token.clone(),
+        session.device_id.clone(),
+        room_default.to_string(),
+        session.refresh_token.clone(),
+    )
+    .save(&gs.credentials_file_path)?;
+    info!(
+        "new credentials file created = {:?}",
+        gs.credentials_file_path
+    );
+    sync_once(&client, get_timeout(gs), gs.ap.sync).await?;
+    Ok(client)
+}
+
 /// Prepares a client that can then be used for actual login.
 /// Configures the matrix-sdk async Client.
 async fn create_client(homeserver: Url, gs: &GlobalState) -> Result<Client, Error> {
@@ -224,21 +393,123 @@ pub(crate) async fn sync_once(client: &Client, timeout: u64, stype: Sync) -> Res
     }
 }
 
-/*pub(crate) fn room(&self, room_id: &RoomId) -> Result<room::Room> {
-    self.get_room(room_id).ok_or(Error::InvalidRoom)
-}*/
+/// Creates a new room, with optional name, topic, alias, preset/visibility
+/// and initial invite list, returning the new room's id.
+pub(crate) async fn create_room(
+    client: &Client,
+    name: Option<&str>,
+    topic: Option<&str>,
+    alias: Option<&str>,
+    preset: Option<RoomPreset>,
+    visibility: Option<Visibility>,
+    invite: &[matrix_sdk::ruma::OwnedUserId],
+) -> Result<OwnedRoomId, Error> {
+    let mut request = assign!(matrix_sdk::ruma::api::client::room::create_room::v3::Request::new(), {
+        name,
+        topic,
+        room_alias_name: alias,
+        preset,
+        invite,
+    });
+    if let Some(visibility) = visibility {
+        request.visibility = visibility;
+    }
+    let response = client.create_room(request).await?;
+    info!("create_room(): created room {}", response.room_id);
+    Ok(response.room_id)
+}
 
-/*pub(crate) fn invited_room(&self, room_id: &RoomId) -> Result<room::Invited> {
-    self.get_invited_room(room_id).ok_or(Error::InvalidRoom)
-}*/
+/// Joins a room by room id or alias. `join_room_by_id_or_alias` accepts
+/// either form directly, so unlike `resolve_room` there is no need to
+/// branch on a leading `#`.
+pub(crate) async fn join_room(client: &Client, room: &str) -> Result<OwnedRoomId, Error> {
+    let room_or_alias = RoomOrAliasId::parse(room).map_err(|_| Error::InvalidRoom)?;
+    let response = client
+        .join_room_by_id_or_alias(&room_or_alias, &[])
+        .await?;
+    info!("join_room(): joined {}", response.room_id);
+    Ok(response.room_id)
+}
 
-// pub(crate) fn joined_room(client: Client, room_id: &RoomId) -> Result<room::Joined> {
-//     client.get_joined_room(room_id).ok_or(Error::InvalidRoom)
-// }
+/// Leaves a joined room.
+pub(crate) async fn leave_room(client: &Client, room: &RoomId) -> Result<(), Error> {
+    client
+        .get_joined_room(room)
+        .ok_or(Error::InvalidRoom)?
+        .leave()
+        .await?;
+    info!("leave_room(): left {}", room);
+    Ok(())
+}
 
-/*pub(crate) fn left_room(&self, room_id: &RoomId) -> Result<room::Left> {
-    self.get_left_room(room_id).ok_or(Error::InvalidRoom)
-}*/
+/// Invites a user to a joined room.
+pub(crate) async fn invite_user(
+    client: &Client,
+    room: &RoomId,
+    user_id: &matrix_sdk::ruma::UserId,
+) -> Result<(), Error> {
+    client
+        .get_joined_room(room)
+        .ok_or(Error::InvalidRoom)?
+        .invite_user_by_id(user_id)
+        .await?;
+    info!("invite_user(): invited {} to {}", user_id, room);
+    Ok(())
+}
+
+/// Enumerates joined, invited and left rooms, printing each with its display
+/// name, member count and encryption status. `Joined`/`Invited`/`Left` all
+/// deref to the same `Common` room state, so all three expose
+/// `active_members_count()` and `is_encrypted()` equally.
+pub(crate) async fn list_rooms(client: &Client) -> Result<(), Error> {
+    for room in client.joined_rooms() {
+        print_room_summary("joined", room.room_id(), room.name(), room.active_members_count(), room.is_encrypted());
+    }
+    for room in client.invited_rooms() {
+        print_room_summary("invited", room.room_id(), room.name(), room.active_members_count(), room.is_encrypted());
+    }
+    for room in client.left_rooms() {
+        print_room_summary("left", room.room_id(), room.name(), room.active_members_count(), room.is_encrypted());
+    }
+    Ok(())
+}
+
+/// Prints a single room's id, display name, member count and encryption
+/// status, in a common format shared by all three room lifecycle states.
+fn print_room_summary(
+    state: &str,
+    room_id: &RoomId,
+    name: Option<String>,
+    member_count: u64,
+    encrypted: bool,
+) {
+    println!(
+        "{} | {} | {} | members: {} | encrypted: {}",
+        state,
+        room_id,
+        name.unwrap_or_default(),
+        member_count,
+        encrypted
+    );
+}
+
+/// Resolves a room id or a room alias (`#alias:server`) to a canonical
+/// `OwnedRoomId`, so callers never have to `.unwrap()` a bare `RoomId::parse()`.
+pub(crate) async fn resolve_room(client: &Client, room: &str) -> Result<OwnedRoomId, Error> {
+    if let Some(rest) = room.strip_prefix('#') {
+        let alias = RoomAliasId::parse(format!("#{}", rest)).map_err(|_| Error::InvalidRoom)?;
+        debug!("resolve_room(): resolving alias {:?}", alias);
+        let response = client
+            .resolve_room_alias(&alias)
+            .await
+            .map_err(|_| Error::InvalidRoom)?;
+        Ok(response.room_id)
+    } else {
+        RoomId::parse(room)
+            .map(|r| r.to_owned())
+            .map_err(|_| Error::InvalidRoom)
+    }
+}
 
 /// Get list of devices for the current user.
 pub(crate) async fn devices(client: &Result<Client, Error>) -> Result<(), Error> {
@@ -306,8 +577,8 @@ pub(crate) async fn message(
             TextMessageEventContent::plain(nmsg)
         })
     };
-    let proom = RoomId::parse(room).unwrap();
-    debug!("In message(): parsed room is {:?}", proom);
+    let proom = resolve_room(client.as_ref().unwrap(), &room).await?;
+    debug!("In message(): resolved room is {:?}", proom);
     client
         .as_ref()
         .unwrap()
@@ -330,7 +601,7 @@ pub(crate) async fn file(
         return Err(Error::InvalidClientConnection);
     }
     let data = fs::read(&filename)?;
-    let proom = RoomId::parse(room).unwrap();
+    let proom = resolve_room(client.as_ref().unwrap(), &room).await?;
     client
         .as_ref()
         .unwrap()
@@ -351,4 +622,129 @@ pub(crate) async fn file(
         )
         .await?;
     Ok(())
+}
+
+/// Exports megolm inbound group sessions from the crypto store to an
+/// encrypted file, optionally restricted to a single room. Uses the standard
+/// Matrix key-export format: an AES-CTR + HMAC-SHA256 key derived from
+/// `passphrase` via PBKDF2-SHA512, wrapped in the
+/// `-----BEGIN/END MEGOLM SESSION DATA-----` base64 armor. The PBKDF2 round
+/// count is fixed internally by `Encryption::export_room_keys` in matrix-sdk
+/// and is not configurable from here.
+pub(crate) async fn export_keys(
+    client: &Result<Client, Error>,
+    path: PathBuf,
+    passphrase: &str,
+    room_filter: Option<&RoomId>,
+) -> Result<(), Error> {
+    if let Ok(client) = client {
+        info!("Exporting room keys to {:?}", path);
+        let predicate = move |session: &matrix_sdk_base::crypto::olm::InboundGroupSession| {
+            room_filter.map_or(true, |room_id| session.room_id() == room_id)
+        };
+        client
+            .encryption()
+            .export_room_keys(path.clone(), passphrase, predicate)
+            .await?;
+        info!("Room keys successfully exported to {:?}", path);
+        Ok(())
+    } else {
+        Err(Error::NotLoggedIn)
+    }
+}
+
+/// Imports megolm inbound group sessions previously written by `export_keys`,
+/// verifying the HMAC before decrypting and feeding the sessions into the
+/// crypto store.
+pub(crate) async fn import_keys(
+    client: &Result<Client, Error>,
+    path: PathBuf,
+    passphrase: &str,
+) -> Result<(), Error> {
+    if let Ok(client) = client {
+        info!("Importing room keys from {:?}", path);
+        let result = client.encryption().import_room_keys(path, passphrase).await?;
+        info!(
+            "Room keys imported: {} imported, {} total",
+            result.imported_count, result.total_count
+        );
+        Ok(())
+    } else {
+        Err(Error::NotLoggedIn)
+    }
+}
+
+/// Prints sender, room, timestamp and body of a single `m.room.message` event.
+fn print_message_event(ev: &SyncRoomMessageEvent, room_id: &RoomId) {
+    if let SyncRoomMessageEvent::Original(ev) = ev {
+        let body = match &ev.content.msgtype {
+            MessageType::Text(t) => t.body.clone(),
+            MessageType::Notice(t) => t.body.clone(),
+            MessageType::Emote(t) => t.body.clone(),
+            MessageType::File(f) => format!("[file: {}]", f.body),
+            MessageType::Image(i) => format!("[image: {}]", i.body),
+            other => format!("[unsupported message type: {}]", other.msgtype()),
+        };
+        println!(
+            "{} | {} | {} | {}",
+            room_id,
+            ev.sender,
+            ev.origin_server_ts.get(),
+            body
+        );
+    }
+}
+
+/// Registers a handler for `m.room.message` events and runs the SDK sync
+/// loop, printing sender, room, timestamp and body for every matched event
+/// until interrupted. Encrypted events are decrypted transparently via the
+/// existing crypto store. With `once` set, only the current batch (since the
+/// stored sync token) is processed: the function returns after that single
+/// sync request, which can wait up to its 30-second timeout for new events.
+pub(crate) async fn listen_forever(
+    client: &Client,
+    rooms: Vec<OwnedRoomId>,
+    once: bool,
+) -> Result<(), Error> {
+    let rooms = std::sync::Arc::new(rooms);
+    let handler_rooms = rooms.clone();
+    client.add_event_handler(move |ev: SyncRoomMessageEvent, room: Room| {
+        let rooms = handler_rooms.clone();
+        async move {
+            if !rooms.is_empty() && !rooms.contains(&room.room_id().to_owned()) {
+                return;
+            }
+            print_message_event(&ev, room.room_id());
+        }
+    });
+
+    let settings = SyncSettings::new().timeout(Duration::from_secs(30));
+    if once {
+        info!("listen_forever(): --once given, processing current batch only");
+        client.sync_once(settings).await?;
+    } else {
+        info!("listen_forever(): entering continuous sync loop");
+        client
+            .sync_with_callback(settings, |_response| async { LoopCtrl::Continue })
+            .await?;
+    }
+    Ok(())
+}
+
+/// Fetches the last `n` timeline events in `room` with one room-messages
+/// pagination request and prints the `m.room.message` events among them,
+/// for users who just want recent history without streaming. Other event
+/// types count towards `n` but are not printed, and no further page is
+/// requested, so fewer than `n` messages can be printed.
+pub(crate) async fn tail(client: &Client, room: &RoomId, n: u32) -> Result<(), Error> {
+    let joined = client.get_joined_room(room).ok_or(Error::InvalidRoom)?;
+    let mut options = matrix_sdk::room::MessagesOptions::new(Direction::Backward);
+    options.limit = UInt::from(n);
+    let response = joined.messages(options).await?;
+    for raw_event in response.chunk.iter().rev() {
+        if let Ok(ev) = raw_event.event.deserialize_as::<SyncRoomMessageEvent>() {
+            print_message_event(&ev, room);
+        }
+    }
+    Ok(())
 }
\ No newline at end of file